@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use sourmash::signature::Signature;
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+use crate::manifest::{self, load_siglist, ManifestRow, Picklist};
+
+/// A loaded signature: a loose file on disk or an entry from a zip archive.
+pub enum SigHandle {
+    Path(PathBuf),
+    ZipEntry { name: String, bytes: Vec<u8> },
+}
+
+impl SigHandle {
+    pub fn load(&self) -> Result<Vec<Signature>, Box<dyn std::error::Error>> {
+        match self {
+            SigHandle::Path(path) => Ok(Signature::from_path(path)?),
+            SigHandle::ZipEntry { bytes, .. } => Ok(serde_json::from_slice(bytes)?),
+        }
+    }
+
+    /// Name to use when writing this signature back out.
+    pub fn output_name(&self) -> String {
+        match self {
+            SigHandle::Path(path) => path
+                .file_name()
+                .expect("signature path must have a file name")
+                .to_string_lossy()
+                .into_owned(),
+            SigHandle::ZipEntry { name, .. } => name.clone(),
+        }
+    }
+}
+
+/// Read the zip's own `SOURMASH-MANIFEST.csv`, if it has one, keyed by
+/// `internal_location`.
+fn read_zip_manifest(
+    archive: &mut ZipArchive<File>,
+) -> Result<Option<HashMap<String, ManifestRow>>, Box<dyn std::error::Error>> {
+    let mut entry = match archive.by_name("SOURMASH-MANIFEST.csv") {
+        Ok(entry) => entry,
+        Err(_) => return Ok(None),
+    };
+    let mut contents = String::new();
+    entry.read_to_string(&mut contents)?;
+    let rows = manifest::parse_manifest_csv(&contents)?;
+    Ok(Some(
+        rows.into_iter()
+            .map(|row| (row.internal_location.clone(), row))
+            .collect(),
+    ))
+}
+
+/// Read the `.sig`/`.sig.gz` entries matching the requested template and
+/// picklist out of a zipped signature collection. The filtering uses the
+/// zip's own manifest; a zip without one has every `.sig`/`.sig.gz` entry
+/// returned unfiltered.
+fn read_zip_entries(
+    path: &Path,
+    ksize: u32,
+    moltype: &str,
+    scaled: usize,
+    picklist: Option<&Picklist>,
+) -> Result<Vec<SigHandle>, Box<dyn std::error::Error>> {
+    let file = File::open(path)?;
+    let mut archive = ZipArchive::new(file)?;
+    let manifest_by_location = read_zip_manifest(&mut archive)?;
+
+    if picklist.is_some() && manifest_by_location.is_none() {
+        return Err(format!(
+            "--picklist requires {:?} to contain a SOURMASH-MANIFEST.csv",
+            path
+        )
+        .into());
+    }
+
+    let mut handles = Vec::with_capacity(archive.len());
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let name = entry.name().to_string();
+        if !(name.ends_with(".sig") || name.ends_with(".sig.gz")) {
+            continue;
+        }
+
+        if let Some(by_location) = &manifest_by_location {
+            match by_location.get(&name) {
+                Some(row) => {
+                    let matches_template =
+                        row.ksize == ksize && row.moltype == moltype && row.scaled as usize == scaled;
+                    let matches_picklist = picklist.map_or(true, |pl| pl.matches(row));
+                    if !matches_template || !matches_picklist {
+                        continue;
+                    }
+                }
+                None => continue,
+            }
+        }
+
+        let mut raw = Vec::new();
+        entry.read_to_end(&mut raw)?;
+        let bytes = if name.ends_with(".gz") {
+            let mut decompressed = Vec::new();
+            flate2::read::GzDecoder::new(Cursor::new(raw)).read_to_end(&mut decompressed)?;
+            decompressed
+        } else {
+            raw
+        };
+
+        handles.push(SigHandle::ZipEntry { name, bytes });
+    }
+
+    Ok(handles)
+}
+
+/// Resolve siglist into signatures: a path list, manifest CSV, or zip.
+pub fn load_search_handles(
+    siglist: &Path,
+    ksize: u32,
+    moltype: &str,
+    scaled: usize,
+    picklist: Option<&Picklist>,
+) -> Result<Vec<SigHandle>, Box<dyn std::error::Error>> {
+    if siglist.extension().and_then(|e| e.to_str()) == Some("zip") {
+        return read_zip_entries(siglist, ksize, moltype, scaled, picklist);
+    }
+
+    let paths = load_siglist(siglist, ksize, moltype, scaled, picklist)?;
+    Ok(paths.into_iter().map(SigHandle::Path).collect())
+}
+
+/// Resolve the query argument the same way as the siglist, except a plain
+/// path that loads as a single signature is taken as-is rather than as a
+/// newline-delimited list of paths.
+pub fn load_query_handles(
+    query: &Path,
+    ksize: u32,
+    moltype: &str,
+    scaled: usize,
+) -> Result<Vec<SigHandle>, Box<dyn std::error::Error>> {
+    let is_zip = query.extension().and_then(|e| e.to_str()) == Some("zip");
+    let is_manifest = crate::manifest::is_manifest_path(query);
+    if !is_zip && !is_manifest && Signature::from_path(query).is_ok() {
+        return Ok(vec![SigHandle::Path(query.to_path_buf())]);
+    }
+    load_search_handles(query, ksize, moltype, scaled, None)
+}
+
+/// Where subtracted signatures are written: a directory or a zip archive.
+///
+/// `Directory` writes each signature to its own file the moment it's
+/// computed, so memory stays O(threads) regardless of collection size.
+/// `Zip` needs a single writer, so it serializes access through a mutex
+/// instead of buffering every signature before writing any of them.
+pub enum SigSink {
+    Directory(PathBuf),
+    Zip {
+        writer: Mutex<Option<ZipWriter<File>>>,
+        manifest_rows: Mutex<Vec<ManifestRow>>,
+    },
+}
+
+impl SigSink {
+    pub fn for_output(output: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+        if output.extension().and_then(|e| e.to_str()) == Some("zip") {
+            Ok(SigSink::Zip {
+                writer: Mutex::new(Some(ZipWriter::new(File::create(output)?))),
+                manifest_rows: Mutex::new(Vec::new()),
+            })
+        } else {
+            Ok(SigSink::Directory(output))
+        }
+    }
+
+    /// Write a single subtracted signature as soon as it's ready. Safe to
+    /// call concurrently from multiple threads.
+    pub fn write_one(
+        &self,
+        name: &str,
+        bytes: &[u8],
+        row: ManifestRow,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            SigSink::Directory(dir) => {
+                let path = dir.join(name);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                File::create(path)?.write_all(bytes)?;
+                Ok(())
+            }
+            SigSink::Zip {
+                writer,
+                manifest_rows,
+            } => {
+                let mut writer_guard = writer.lock().unwrap();
+                let writer = writer_guard.as_mut().expect("write_one called after finish");
+                writer.start_file(name, FileOptions::default())?;
+                writer.write_all(bytes)?;
+                manifest_rows.lock().unwrap().push(row);
+                Ok(())
+            }
+        }
+    }
+
+    /// Finalize the sink once every signature has been written: a no-op for
+    /// `Directory`, but `Zip` still needs its trailing manifest entry.
+    pub fn finish(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            SigSink::Directory(_) => Ok(()),
+            SigSink::Zip {
+                writer,
+                manifest_rows,
+            } => {
+                let mut manifest_csv = Vec::new();
+                writeln!(manifest_csv, "# SOURMASH-MANIFEST-VERSION: 1.0")?;
+                {
+                    let mut csv_writer = csv::Writer::from_writer(&mut manifest_csv);
+                    for row in manifest_rows.lock().unwrap().iter() {
+                        csv_writer.serialize(row)?;
+                    }
+                    csv_writer.flush()?;
+                }
+
+                let mut writer_guard = writer.lock().unwrap();
+                let mut writer = writer_guard.take().expect("finish called twice");
+                writer.start_file("SOURMASH-MANIFEST.csv", FileOptions::default())?;
+                writer.write_all(&manifest_csv)?;
+                writer.finish()?;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_zip_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("subtract-test-{}-{}.zip", std::process::id(), name))
+    }
+
+    /// A zip with two sketches of different moltype/ksize, plus a manifest
+    /// describing them, mimicking a real heterogeneous collection.
+    fn write_mixed_moltype_zip(path: &Path) {
+        let mut writer = ZipWriter::new(File::create(path).unwrap());
+        let options = FileOptions::default();
+
+        writer.start_file("dna.sig", options).unwrap();
+        writer.write_all(b"{}").unwrap();
+        writer.start_file("protein.sig", options).unwrap();
+        writer.write_all(b"{}").unwrap();
+
+        writer.start_file("SOURMASH-MANIFEST.csv", options).unwrap();
+        writer.write_all(
+            b"# SOURMASH-MANIFEST-VERSION: 1.0\n\
+internal_location,md5,md5short,ksize,moltype,num,scaled,n_hashes,with_abundance,name,filename\n\
+dna.sig,aaa,aaa,31,dna,0,10,5,0,dna-sig,dna.sig\n\
+protein.sig,bbb,bbb,93,protein,0,10,5,0,protein-sig,protein.sig\n",
+        )
+        .unwrap();
+
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn read_zip_entries_skips_entries_not_matching_template() {
+        let path = temp_zip_path("mixed-moltype");
+        write_mixed_moltype_zip(&path);
+
+        let handles = read_zip_entries(&path, 93, "protein", 10, None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(handles.len(), 1);
+        assert_eq!(handles[0].output_name(), "protein.sig");
+    }
+
+    #[test]
+    fn read_zip_entries_rejects_picklist_without_zip_manifest() {
+        let path = temp_zip_path("no-manifest");
+        let mut writer = ZipWriter::new(File::create(&path).unwrap());
+        writer.start_file("a.sig", FileOptions::default()).unwrap();
+        writer.write_all(b"{}").unwrap();
+        writer.finish().unwrap();
+
+        let picklist_path = std::env::temp_dir().join(format!(
+            "subtract-test-{}-picklist.csv",
+            std::process::id()
+        ));
+        std::fs::write(&picklist_path, "md5\naaa\n").unwrap();
+        let picklist = Picklist::from_spec(&format!("{}:md5:md5", picklist_path.display())).unwrap();
+
+        let result = read_zip_entries(&path, 31, "dna", 10, Some(&picklist));
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&picklist_path).unwrap();
+
+        assert!(result.is_err());
+    }
+}
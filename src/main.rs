@@ -1,20 +1,26 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter};
+mod collection;
+mod manifest;
+
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
-use log::info;
+use log::{info, warn};
 use rayon::prelude::*;
 use sourmash::signature::Signature;
-use sourmash::sketch::minhash::{
-    max_hash_for_scaled, HashFunctions, KmerMinHash, KmerMinHashBTree,
-};
+use sourmash::sketch::minhash::{max_hash_for_scaled, HashFunctions, KmerMinHash};
 use sourmash::sketch::Sketch;
 use structopt::StructOpt;
 
+use collection::{load_query_handles, load_search_handles, SigSink};
+use manifest::{ManifestRow, Picklist};
+
 #[derive(StructOpt, Debug)]
 struct Cli {
-    /// Query to be subtracted
+    /// Query (or queries) to be subtracted: a single signature, a siglist, a
+    /// manifest, or a zip collection. All matching sketches are unioned
+    /// before subtraction.
     #[structopt(parse(from_os_str))]
     query: PathBuf,
 
@@ -30,9 +36,92 @@ struct Cli {
     #[structopt(short = "s", long = "scaled", default_value = "10")]
     scaled: usize,
 
+    /// Molecule type of the signatures being compared
+    #[structopt(
+        long = "moltype",
+        default_value = "protein",
+        possible_values = &["dna", "protein", "dayhoff", "hp"]
+    )]
+    moltype: String,
+
+    /// Restrict the siglist to signatures matching FILE:COLUMN:field, where
+    /// field is one of md5, md5short, name, ident. Requires siglist to be a
+    /// manifest, or a zip collection with a manifest inside it.
+    #[structopt(long = "picklist")]
+    picklist: Option<String>,
+
     /// The path for output
     #[structopt(parse(from_os_str), short = "o", long = "output")]
     output: Option<PathBuf>,
+
+    /// Write a CSV of per-signature overlap, containment, and ANI stats
+    #[structopt(parse(from_os_str), long = "stats-csv")]
+    stats_csv: Option<PathBuf>,
+}
+
+/// Per-signature overlap, containment, and ANI against the query.
+#[derive(Debug, serde::Serialize)]
+struct SubtractionStats {
+    signature_name: String,
+    signature_md5: String,
+    intersect_hashes: usize,
+    containment: f64,
+    ani: f64,
+    /// Sum of abundances removed by the subtraction; 0 for signatures that
+    /// don't track abundance.
+    removed_abundance: u64,
+    /// Sum of abundances of the hashes that survived the subtraction.
+    remaining_abundance: u64,
+}
+
+/// ANI from containment: `ani = containment^(1/ksize)`.
+fn containment_to_ani(containment: f64, ksize: u32) -> f64 {
+    containment.powf(1.0 / ksize as f64)
+}
+
+/// Map `--moltype` to its `HashFunctions` variant.
+fn hash_function_for_moltype(moltype: &str) -> HashFunctions {
+    match moltype {
+        "dna" => HashFunctions::murmur64_DNA,
+        "protein" => HashFunctions::murmur64_protein,
+        "dayhoff" => HashFunctions::murmur64_dayhoff,
+        "hp" => HashFunctions::murmur64_hp,
+        _ => unreachable!("structopt possible_values should have rejected this"),
+    }
+}
+
+/// Union several sketches' hash lists into the set of hashes to remove.
+fn union_hashes(hash_lists: impl IntoIterator<Item = Vec<u64>>) -> HashSet<u64> {
+    let mut union = HashSet::new();
+    for hashes in hash_lists {
+        union.extend(hashes);
+    }
+    union
+}
+
+/// Split a sketch's per-hash abundances into what's removed (hashes in
+/// `to_remove`) and what remains.
+fn split_abundance(mins: &[u64], abunds: &[u64], to_remove: &HashSet<u64>) -> (u64, u64) {
+    let mut removed = 0u64;
+    let mut remaining = 0u64;
+    for (hash, abund) in mins.iter().zip(abunds.iter()) {
+        if to_remove.contains(hash) {
+            removed += abund;
+        } else {
+            remaining += abund;
+        }
+    }
+    (removed, remaining)
+}
+
+/// Protein-space sketches store ksize as `3 * ksize`; DNA uses it verbatim.
+fn scaled_ksize(ksize: u8, hash_function: &HashFunctions) -> u32 {
+    match hash_function {
+        HashFunctions::murmur64_protein | HashFunctions::murmur64_dayhoff | HashFunctions::murmur64_hp => {
+            ksize as u32 * 3
+        }
+        _ => ksize as u32,
+    }
 }
 
 fn subtract<P: AsRef<Path>>(
@@ -40,83 +129,157 @@ fn subtract<P: AsRef<Path>>(
     siglist: P,
     ksize: u8,
     scaled: usize,
+    moltype: &str,
+    picklist: Option<&str>,
     output: Option<P>,
+    stats_csv: Option<P>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     info!("Loading queries");
 
+    let hash_function = hash_function_for_moltype(moltype);
+    let template_ksize = scaled_ksize(ksize, &hash_function);
     let max_hash = max_hash_for_scaled(scaled as u64);
     let template_mh = KmerMinHash::builder()
         .num(0u32)
-        .ksize(ksize as u32)
-        .hash_function(HashFunctions::murmur64_protein)
+        .ksize(template_ksize)
+        .hash_function(hash_function)
         .max_hash(max_hash)
         .build();
     let template = Sketch::MinHash(template_mh);
 
-    let query_sig = Signature::from_path(query).unwrap();
-    let mut query: Option<KmerMinHashBTree> = None;
-    for sig in &query_sig {
-        if let Some(sketch) = sig.select_sketch(&template) {
-            if let Sketch::MinHash(mh) = sketch {
-                query = Some(mh.clone().into());
+    let query_handles = load_query_handles(query.as_ref(), template_ksize, moltype, scaled)?;
+    let mut query_mins: Vec<Vec<u64>> = Vec::new();
+    for handle in &query_handles {
+        for sig in &handle.load()? {
+            if let Some(sketch) = sig.select_sketch(&template) {
+                if let Sketch::MinHash(mh) = sketch {
+                    query_mins.push(mh.mins());
+                }
             }
         }
     }
-    let query = query.unwrap();
-    info!("Loaded query signature, k={}", ksize);
-    let hashes_to_remove = query.mins();
+    let n_query_sketches = query_mins.len();
+    let hashes_to_remove_set = union_hashes(query_mins);
+    info!(
+        "Loaded {} query signature(s), union of {} hashes",
+        n_query_sketches,
+        hashes_to_remove_set.len()
+    );
+    let hashes_to_remove: Vec<u64> = hashes_to_remove_set.iter().cloned().collect();
+    let query_size = hashes_to_remove_set.len();
 
     info!("Loading siglist");
-    let siglist_file = BufReader::new(File::open(siglist)?);
-    let search_sigs: Vec<PathBuf> = siglist_file
-        .lines()
-        .map(|line| {
-            let mut path = PathBuf::new();
-            path.push(line.unwrap());
-            path
-        })
-        .collect();
+    let picklist = picklist.map(Picklist::from_spec).transpose()?;
+    let search_sigs = load_search_handles(
+        siglist.as_ref(),
+        template_ksize,
+        moltype,
+        scaled,
+        picklist.as_ref(),
+    )?;
     info!("Loaded {} sig paths in siglist", search_sigs.len());
 
-    let mut outdir: PathBuf = if let Some(p) = output {
-        p.as_ref().into()
-    } else {
-        let mut path = PathBuf::new();
-        path.push("outputs");
-        path
+    let sink = match output {
+        Some(p) => SigSink::for_output(p.as_ref().into())?,
+        None => SigSink::for_output(PathBuf::from("outputs").join(format!("{}", ksize)))?,
     };
-    outdir.push(format!("{}", ksize));
-    std::fs::create_dir_all(&outdir)?;
+    let stats_writer = stats_csv
+        .map(csv::Writer::from_path)
+        .transpose()?
+        .map(Mutex::new);
 
     let processed_sigs = AtomicUsize::new(0);
 
-    search_sigs.par_iter().for_each(|filename| {
-        let i = processed_sigs.fetch_add(1, Ordering::SeqCst);
-        if i % 1000 == 0 {
-            info!("Processed {} sigs", i);
-        }
+    search_sigs
+        .par_iter()
+        .try_for_each(|handle| -> Result<(), Box<dyn std::error::Error>> {
+            let i = processed_sigs.fetch_add(1, Ordering::SeqCst);
+            if i % 1000 == 0 {
+                info!("Processed {} sigs", i);
+            }
+
+            let name = handle.output_name();
+            let mut search_sig = handle
+                .load()
+                .unwrap_or_else(|e| panic!("Error processing {:?}: {}", name, e))
+                .swap_remove(0);
 
-        let mut search_mh = None;
-        let mut search_sig = Signature::from_path(&filename)
-            .unwrap_or_else(|_| panic!("Error processing {:?}", filename))
-            .swap_remove(0);
-        if let Some(sketch) = search_sig.select_sketch(&template) {
-            if let Sketch::MinHash(mh) = sketch {
-                search_mh = Some(mh.clone());
+            let mut search_mh = None;
+            if let Some(sketch) = search_sig.select_sketch(&template) {
+                if let Sketch::MinHash(mh) = sketch {
+                    search_mh = Some(mh.clone());
+                }
             }
-        }
-        let mut search_mh = search_mh.unwrap();
+            let mut search_mh = match search_mh {
+                Some(mh) => mh,
+                None => {
+                    warn!("Skipping {:?}: no sketch matching ksize={}, moltype={}", name, ksize, moltype);
+                    return Ok(());
+                }
+            };
 
-        search_mh.remove_many(&hashes_to_remove).unwrap();
-        // TODO: save to output dir
-        let mut path = outdir.clone();
-        path.push(filename.file_name().unwrap());
+            let search_mins: HashSet<u64> = search_mh.mins().into_iter().collect();
+            let intersect_hashes = hashes_to_remove_set.intersection(&search_mins).count();
+            let containment = if query_size > 0 {
+                intersect_hashes as f64 / query_size as f64
+            } else {
+                0.0
+            };
+            let ani = containment_to_ani(containment, template_ksize);
 
-        let mut out = BufWriter::new(File::create(path).unwrap());
-        search_sig.reset_sketches();
-        search_sig.push(Sketch::MinHash(search_mh));
-        serde_json::to_writer(&mut out, &[search_sig]).unwrap();
-    });
+            let (removed_abundance, remaining_abundance) = if search_mh.track_abundance() {
+                split_abundance(
+                    &search_mh.mins(),
+                    &search_mh.abunds().unwrap_or_default(),
+                    &hashes_to_remove_set,
+                )
+            } else {
+                (0, 0)
+            };
+
+            search_mh.remove_many(&hashes_to_remove).unwrap();
+
+            let n_hashes = search_mh.mins().len();
+            let with_abundance = search_mh.track_abundance() as u8;
+            let md5 = search_mh.md5sum();
+            search_sig.reset_sketches();
+            search_sig.push(Sketch::MinHash(search_mh));
+
+            let stats = SubtractionStats {
+                signature_name: search_sig.name(),
+                signature_md5: md5.clone(),
+                intersect_hashes,
+                containment,
+                ani,
+                removed_abundance,
+                remaining_abundance,
+            };
+            if let Some(stats_writer) = &stats_writer {
+                stats_writer.lock().unwrap().serialize(&stats)?;
+            }
+
+            let bytes = serde_json::to_vec(&[&search_sig]).unwrap();
+            let row = ManifestRow {
+                internal_location: name.clone(),
+                md5short: md5[..8].to_string(),
+                md5,
+                ksize: template_ksize,
+                moltype: moltype.to_string(),
+                num: 0,
+                scaled: scaled as u64,
+                n_hashes,
+                with_abundance,
+                name: search_sig.name(),
+                filename: name.clone(),
+            };
+
+            sink.write_one(&name, &bytes, row)
+        })?;
+
+    if let Some(stats_writer) = stats_writer {
+        stats_writer.into_inner().unwrap().flush()?;
+    }
+    sink.finish()?;
 
     Ok(())
 }
@@ -131,8 +294,73 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         opts.siglist,
         opts.ksize,
         opts.scaled,
+        &opts.moltype,
+        opts.picklist.as_deref(),
         opts.output,
+        opts.stats_csv,
     )?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn containment_to_ani_is_one_at_full_containment() {
+        assert_eq!(containment_to_ani(1.0, 31), 1.0);
+    }
+
+    #[test]
+    fn containment_to_ani_matches_closed_form() {
+        let ani = containment_to_ani(0.5, 21);
+        assert!((ani - 0.5f64.powf(1.0 / 21.0)).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn scaled_ksize_multiplies_protein_space_by_three() {
+        assert_eq!(scaled_ksize(10, &HashFunctions::murmur64_protein), 30);
+        assert_eq!(scaled_ksize(10, &HashFunctions::murmur64_dayhoff), 30);
+        assert_eq!(scaled_ksize(10, &HashFunctions::murmur64_hp), 30);
+    }
+
+    #[test]
+    fn scaled_ksize_leaves_dna_verbatim() {
+        assert_eq!(scaled_ksize(31, &HashFunctions::murmur64_DNA), 31);
+    }
+
+    #[test]
+    fn union_hashes_is_the_union_of_every_query_sketch() {
+        let union = union_hashes(vec![vec![1, 2, 3], vec![3, 4, 5]]);
+        let expected: HashSet<u64> = [1, 2, 3, 4, 5].into_iter().collect();
+        assert_eq!(union, expected);
+    }
+
+    #[test]
+    fn union_hashes_is_empty_for_no_queries() {
+        assert!(union_hashes(Vec::<Vec<u64>>::new()).is_empty());
+    }
+
+    #[test]
+    fn split_abundance_sums_to_the_original_total() {
+        let mins = vec![1, 2, 3, 4];
+        let abunds = vec![10, 20, 30, 40];
+        let to_remove: HashSet<u64> = [2, 4].into_iter().collect();
+
+        let (removed, remaining) = split_abundance(&mins, &abunds, &to_remove);
+
+        assert_eq!(removed, 60);
+        assert_eq!(remaining, 40);
+        assert_eq!(removed + remaining, abunds.iter().sum::<u64>());
+    }
+
+    #[test]
+    fn split_abundance_keeps_everything_when_nothing_is_removed() {
+        let mins = vec![1, 2, 3];
+        let abunds = vec![5, 5, 5];
+        let (removed, remaining) = split_abundance(&mins, &abunds, &HashSet::new());
+        assert_eq!(removed, 0);
+        assert_eq!(remaining, 15);
+    }
+}
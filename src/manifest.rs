@@ -0,0 +1,265 @@
+use std::collections::HashSet;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+/// One row of a sourmash manifest CSV.
+///
+/// Mirrors the columns sourmash writes in `sourmash sig manifest`: enough
+/// metadata to locate and pre-filter a signature without opening it.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ManifestRow {
+    pub internal_location: String,
+    pub md5: String,
+    pub md5short: String,
+    pub ksize: u32,
+    pub moltype: String,
+    pub num: u32,
+    pub scaled: u64,
+    #[serde(default)]
+    pub n_hashes: usize,
+    #[serde(default)]
+    pub with_abundance: u8,
+    pub name: String,
+    #[serde(default)]
+    pub filename: String,
+}
+
+/// A `--picklist FILE:COLUMN:field` selection: the allowed values read from
+/// `COLUMN` in the picklist file, matched against a manifest row's `field`
+/// (one of `md5`, `md5short`, `name`, `ident`).
+pub struct Picklist {
+    pub field: String,
+    values: HashSet<String>,
+}
+
+impl Picklist {
+    pub fn from_spec(spec: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let parts: Vec<&str> = spec.splitn(3, ':').collect();
+        let (path, column, field) = match parts.as_slice() {
+            [path, column, field] => (*path, *column, *field),
+            _ => {
+                return Err(format!(
+                    "--picklist must be FILE:COLUMN:field, got {:?}",
+                    spec
+                )
+                .into())
+            }
+        };
+        if !matches!(field, "md5" | "md5short" | "name" | "ident") {
+            return Err(format!(
+                "--picklist field must be one of md5, md5short, name, ident, got {:?}",
+                field
+            )
+            .into());
+        }
+
+        let mut reader = csv::Reader::from_path(path)?;
+        let col_index = reader
+            .headers()?
+            .iter()
+            .position(|h| h == column)
+            .ok_or_else(|| format!("column {:?} not found in picklist {:?}", column, path))?;
+
+        let mut values = HashSet::new();
+        for record in reader.records() {
+            let record = record?;
+            if let Some(value) = record.get(col_index) {
+                values.insert(value.to_string());
+            }
+        }
+
+        Ok(Picklist {
+            field: field.to_string(),
+            values,
+        })
+    }
+
+    pub fn matches(&self, row: &ManifestRow) -> bool {
+        let value: &str = match self.field.as_str() {
+            "md5" => &row.md5,
+            "md5short" => &row.md5short,
+            "name" => &row.name,
+            "ident" => row.name.split_whitespace().next().unwrap_or(&row.name),
+            _ => return false,
+        };
+        self.values.contains(value)
+    }
+}
+
+/// Manifests are plain CSV, optionally preceded by sourmash's
+/// `# SOURMASH-MANIFEST-VERSION` sentinel comment line.
+const MANIFEST_SENTINEL: &str = "# SOURMASH-MANIFEST-VERSION";
+
+/// Sniff whether `path` looks like a sourmash manifest rather than a plain
+/// newline-delimited siglist, by extension or by its first line.
+pub fn is_manifest_path(path: &Path) -> bool {
+    if path.extension().and_then(|e| e.to_str()) == Some("csv") {
+        return true;
+    }
+    let file = match std::fs::File::open(path) {
+        Ok(f) => f,
+        Err(_) => return false,
+    };
+    let mut first_line = String::new();
+    if std::io::BufReader::new(file)
+        .read_line(&mut first_line)
+        .is_ok()
+    {
+        first_line.starts_with(MANIFEST_SENTINEL)
+    } else {
+        false
+    }
+}
+
+/// Parse manifest CSV text, stripping the sourmash sentinel line if present.
+pub fn parse_manifest_csv(contents: &str) -> Result<Vec<ManifestRow>, Box<dyn std::error::Error>> {
+    let csv_body = match contents.strip_prefix(MANIFEST_SENTINEL) {
+        Some(_) => contents.splitn(2, '\n').nth(1).unwrap_or(""),
+        None => contents,
+    };
+
+    let mut reader = csv::Reader::from_reader(csv_body.as_bytes());
+    let mut rows = Vec::new();
+    for result in reader.deserialize() {
+        rows.push(result?);
+    }
+    Ok(rows)
+}
+
+pub fn load_manifest(path: &Path) -> Result<Vec<ManifestRow>, Box<dyn std::error::Error>> {
+    parse_manifest_csv(&std::fs::read_to_string(path)?)
+}
+
+/// Load a siglist, resolving a manifest's `internal_location` entries
+/// relative to the manifest's own directory and pre-filtering rows that
+/// don't match the requested template or picklist before any file I/O.
+pub fn load_siglist(
+    siglist: &Path,
+    ksize: u32,
+    moltype: &str,
+    scaled: usize,
+    picklist: Option<&Picklist>,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    if is_manifest_path(siglist) {
+        let base_dir = siglist.parent().unwrap_or_else(|| Path::new("."));
+        let rows = load_manifest(siglist)?;
+        let paths = rows
+            .into_iter()
+            .filter(|row| {
+                row.ksize == ksize && row.moltype == moltype && row.scaled as usize == scaled
+            })
+            .filter(|row| picklist.map_or(true, |pl| pl.matches(row)))
+            .map(|row| base_dir.join(&row.internal_location))
+            .collect();
+        Ok(paths)
+    } else {
+        if picklist.is_some() {
+            return Err(format!(
+                "--picklist requires siglist to be a manifest, got a plain siglist: {:?}",
+                siglist
+            )
+            .into());
+        }
+        let siglist_file = std::io::BufReader::new(std::fs::File::open(siglist)?);
+        Ok(siglist_file
+            .lines()
+            .map(|line| PathBuf::from(line.unwrap()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("subtract-test-{}-{}", std::process::id(), name))
+    }
+
+    fn write_temp(name: &str, contents: &str) -> PathBuf {
+        let path = temp_path(name);
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn picklist_rejects_malformed_spec() {
+        assert!(Picklist::from_spec("not-enough-parts").is_err());
+    }
+
+    #[test]
+    fn picklist_rejects_unknown_field() {
+        let path = write_temp("picklist-field.csv", "name\nfoo\n");
+        let err = Picklist::from_spec(&format!("{}:name:bogus", path.display()));
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn picklist_rejects_missing_column() {
+        let path = write_temp("picklist-column.csv", "name\nfoo\n");
+        let err = Picklist::from_spec(&format!("{}:md5:md5", path.display()));
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn picklist_matches_requested_column_and_field() {
+        let path = write_temp("picklist-match.csv", "md5\nabc123\n");
+        let picklist = Picklist::from_spec(&format!("{}:md5:md5", path.display())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let row = ManifestRow {
+            internal_location: "sig.sig".into(),
+            md5: "abc123".into(),
+            md5short: "abc123"[..6].into(),
+            ksize: 31,
+            moltype: "protein".into(),
+            num: 0,
+            scaled: 10,
+            n_hashes: 0,
+            with_abundance: 0,
+            name: "foo".into(),
+            filename: "sig.sig".into(),
+        };
+        assert!(picklist.matches(&row));
+    }
+
+    #[test]
+    fn is_manifest_path_detects_csv_extension() {
+        let path = temp_path("foo.csv");
+        assert!(is_manifest_path(&path));
+    }
+
+    #[test]
+    fn is_manifest_path_detects_sentinel_line() {
+        let path = write_temp(
+            "manifest.txt",
+            "# SOURMASH-MANIFEST-VERSION: 1.0\ninternal_location\n",
+        );
+        let is_manifest = is_manifest_path(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(is_manifest);
+    }
+
+    #[test]
+    fn is_manifest_path_rejects_plain_siglist() {
+        let path = write_temp("siglist.txt", "a.sig\nb.sig\n");
+        let is_manifest = is_manifest_path(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(!is_manifest);
+    }
+
+    #[test]
+    fn load_manifest_strips_sentinel_line() {
+        let contents = "# SOURMASH-MANIFEST-VERSION: 1.0\ninternal_location,md5,md5short,ksize,moltype,num,scaled,n_hashes,with_abundance,name,filename\nfoo.sig,abc123,abc123,31,protein,0,10,5,0,foo,foo.sig\n";
+        let rows = parse_manifest_csv(contents).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].internal_location, "foo.sig");
+        assert_eq!(rows[0].ksize, 31);
+    }
+}